@@ -1,38 +1,113 @@
 
-use std::{collections::HashSet, str::FromStr, sync::Arc};
-use anyhow::{Context, Result};
-use axum::{routing::get, Router, Json};
+use std::{collections::{HashMap, HashSet}, str::FromStr, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use anyhow::{anyhow, Context, Result};
+use axum::{http::StatusCode, routing::get, Router, Json};
 use dotenvy::dotenv;
 use ethers::{
     prelude::*,
     types::{Filter, H160, H256, Log, U256, BlockNumber},
 };
+use futures::StreamExt;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_yaml;
-use sqlx::{SqlitePool, Sqlite, migrate::MigrateDatabase};
-use tokio::{sync::RwLock, task::JoinSet};
+use tokio::sync::RwLock;
 use tracing::{info, warn, error};
 use tracing_subscriber::{EnvFilter, fmt::Subscriber};
 
+mod metrics;
+mod provider;
+mod storage;
+use provider::MultiProvider;
+use storage::{sub_decimal, sub_human, NetflowDelta, NetflowTotal, Storage, Transfer};
+
+/// Majority of 3 endpoints, the default shape this indexer is tuned for.
+fn default_quorum() -> usize { 2 }
+
 static TRANSFER_TOPIC: Lazy<H256> = Lazy::new(|| H256::from_slice(
     // keccak256("Transfer(address,address,uint256)")
     &hex_literal::hex!("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")
 ));
 
+/// Starting window size (in blocks) for the historical backfill range query.
+const BACKFILL_INITIAL_WINDOW: u64 = 2000;
+/// Never grow the backfill window past this many blocks per `get_logs` call.
+const BACKFILL_MAX_WINDOW: u64 = 2000;
+/// Never shrink the backfill window below this; at this size each `get_logs` call covers a single block.
+const BACKFILL_MIN_WINDOW: u64 = 1;
+/// Initial backoff before retrying a rate-limited backfill `get_logs` call at the same window.
+const BACKFILL_RATE_LIMIT_BASE_DELAY_MS: u64 = 500;
+/// Cap on the backoff delay between rate-limited backfill retries.
+const BACKFILL_RATE_LIMIT_MAX_DELAY_MS: u64 = 30_000;
+
+/// How many attempts in total (the first call plus up to four retries) `retry_rpc` makes at a
+/// transiently failing call in the live indexing path before giving up and letting it end
+/// `run_indexer`.
+const INDEXER_RETRY_ATTEMPTS: u32 = 5;
+/// Initial backoff before the first `retry_rpc` retry.
+const INDEXER_RETRY_BASE_DELAY_MS: u64 = 1000;
+/// Cap on the backoff delay between `retry_rpc` retries.
+const INDEXER_RETRY_MAX_DELAY_MS: u64 = 30_000;
+
+/// One watched ERC20 token. `decimals` scales raw on-chain amounts into the human-readable values
+/// reported alongside the raw ones, the way the token's own contract would display them.
+#[derive(Debug, Clone, Deserialize)]
+struct TokenConfig {
+    address: String,
+    symbol: String,
+    decimals: u32,
+}
+
 #[derive(Debug, Deserialize)]
 struct AppConfig {
-    token_address: String,
-    binance_addresses: Vec<String>,
+    /// Every token this indexer tracks Transfer logs for.
+    tokens: Vec<TokenConfig>,
+    /// Named sets of exchange addresses (e.g. "binance", "coinbase") to net flows against. A
+    /// transfer can match more than one exchange if an address appears in multiple sets.
+    exchanges: HashMap<String, Vec<String>>,
     #[serde(default)]
     start_from_block: Option<u64>,
+    /// RPC endpoints dispatched to in parallel; reads succeed once `quorum` of them agree.
+    rpc_endpoints: Vec<String>,
+    /// Number of endpoints that must agree before a read is accepted. Clamped to the number of
+    /// configured endpoints, so a single-endpoint config degrades to plain failover.
+    #[serde(default = "default_quorum")]
+    quorum: usize,
+    /// Optional WebSocket endpoint used to subscribe to new block headers in near-real-time.
+    /// When absent (or when the subscription drops), the indexer falls back to polling
+    /// `get_block_number` through the quorum provider.
+    #[serde(default)]
+    ws_endpoint: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize)]
-struct Netflow {
+/// Running netflow for one (token, exchange) pair, reported in both raw (wei-scale) and
+/// human (decimal-scaled by the token's `decimals`) form.
+#[derive(Debug, Clone, Serialize)]
+struct NetflowEntry {
+    token_symbol: String,
+    token_address: String,
+    exchange: String,
     inflow: String,
     outflow: String,
     cumulative: String,
+    inflow_human: String,
+    outflow_human: String,
+    cumulative_human: String,
+}
+
+/// The set of tokens and exchange address books the indexer watches, normalized once at startup
+/// and threaded through the indexing path instead of the raw `AppConfig`.
+struct WatchConfig {
+    /// Normalized token address -> its config (symbol, decimals).
+    tokens: HashMap<String, TokenConfig>,
+    /// Exchange name -> its normalized address set.
+    exchanges: HashMap<String, HashSet<String>>,
+}
+
+impl WatchConfig {
+    fn token_addresses(&self) -> Result<Vec<H160>> {
+        self.tokens.keys().map(|a| H160::from_str(a).context("bad token address")).collect()
+    }
 }
 
 #[tokio::main(flavor="multi_thread")]
@@ -42,190 +117,444 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber).ok();
 
     let db_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://./data/indexer.sqlite".into());
-    if !Sqlite::database_exists(&db_url).await.unwrap_or(false) {
-        Sqlite::create_database(&db_url).await?;
-    }
-    let pool = SqlitePool::connect(&db_url).await?;
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    let storage: Arc<dyn Storage> = storage::connect(&db_url).await?;
 
     // Read config
     let cfg_text = std::fs::read_to_string("config.yaml").context("missing config.yaml")?;
     let cfg: AppConfig = serde_yaml::from_str(&cfg_text).context("invalid config.yaml")?;
 
-    // Normalize addresses to lowercase
-    let token = normalize(&cfg.token_address);
-    let binance: HashSet<String> = cfg.binance_addresses.into_iter().map(normalize).collect();
-
-    // Provider
-    let wss = std::env::var("POLYGON_WSS").unwrap_or_else(|_| "wss://polygon-rpc.com".into());
-    let ws = Ws::connect(wss.clone()).await.context("WS connect failed")?;
-    let provider = Provider::new(ws).interval(std::time::Duration::from_millis(200));
-    let provider = Arc::new(provider);
-
-    // If start_from_block is provided, store it into state; else set to latest (no backfill)
+    // Normalize addresses to lowercase and index tokens/exchanges for fast lookup while indexing.
+    let tokens: HashMap<String, TokenConfig> = cfg.tokens.iter()
+        .map(|t| (normalize(&t.address), t.clone()))
+        .collect();
+    let exchanges: HashMap<String, HashSet<String>> = cfg.exchanges.into_iter()
+        .map(|(name, addrs)| (name, addrs.into_iter().map(|a| normalize(&a)).collect()))
+        .collect();
+    let watch = Arc::new(WatchConfig { tokens, exchanges });
+
+    // Provider: fan reads out across every configured endpoint and require `quorum` of them to
+    // agree, so one node dropping its connection or disagreeing doesn't stall indexing.
+    let provider = Arc::new(MultiProvider::new(&cfg.rpc_endpoints, cfg.quorum)?);
+
+    // If start_from_block is provided, store it into state; else set to latest (no backfill).
+    // `last_block` means "last block already processed", so seed one below `start` or the first
+    // backfill window would skip it.
     if let Some(start) = cfg.start_from_block {
-        sqlx::query("INSERT OR REPLACE INTO state (key, value) VALUES ('last_block', ?)")
-            .bind(start.to_string()).execute(&pool).await?;
+        storage.save_block_state(start.saturating_sub(1)).await?;
     } else {
         let latest: U64 = provider.get_block_number().await?;
-        sqlx::query("INSERT OR REPLACE INTO state (key, value) VALUES ('last_block', ?)")
-            .bind(latest.as_u64().to_string()).execute(&pool).await?;
+        storage.save_block_state(latest.as_u64()).await?;
     }
 
-    let net = Arc::new(RwLock::new(Netflow::default()));
-    // Preload cached totals
+    let net: Arc<RwLock<HashMap<(String, String), NetflowEntry>>> = Arc::new(RwLock::new(HashMap::new()));
+    // Preload cached totals, one entry per (token, exchange) pair already seen.
     {
-        if let Ok(rec) = sqlx::query_as::<_, (String, String)>("SELECT inflow, outflow FROM netflow_totals WHERE id=1").fetch_one(&pool).await {
-            let (inflow, outflow) = rec;
-            let cumulative = sub_decimal(&inflow, &outflow);
-            *net.write().await = Netflow{ inflow, outflow, cumulative };
+        if let Ok(totals) = storage.load_netflow_totals().await {
+            let mut guard = net.write().await;
+            for t in totals {
+                guard.insert((t.token_address.clone(), t.exchange.clone()), netflow_entry(&watch, t));
+            }
         }
     }
 
     // HTTP server
-    let http_pool = pool.clone();
+    // Flipped to false once the indexer task terminates (see the watcher spawned below), so a
+    // crashed indexer is visible from the outside instead of looking identical to a healthy one.
+    let indexer_healthy = Arc::new(AtomicBool::new(true));
+    let health_flag = indexer_healthy.clone();
     let http_net = net.clone();
     let app = Router::new()
-        .route("/health", get(|| async { "ok" }))
+        .route("/health", get(move || {
+            let healthy = health_flag.clone();
+            async move {
+                if healthy.load(Ordering::Relaxed) {
+                    (StatusCode::OK, "ok")
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, "indexer stopped")
+                }
+            }
+        }))
         .route("/netflow", get(move || {
             let net = http_net.clone();
             async move {
-                let current = net.read().await.clone();
+                let current: Vec<NetflowEntry> = net.read().await.values().cloned().collect();
                 Json(current)
             }
-        }));
+        }))
+        .route("/metrics", get(|| async { metrics::render() }));
 
     let bind = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
     let listener = tokio::net::TcpListener::bind(&bind).await?;
     info!("HTTP server listening on http://{}", bind);
 
     // Spawn indexer
-    let indexer = tokio::spawn(run_indexer(provider.clone(), pool.clone(), token.clone(), binance.clone(), net.clone()));
+    let indexer = tokio::spawn(run_indexer(provider.clone(), storage.clone(), watch.clone(), net.clone(), cfg.ws_endpoint.clone()));
+
+    // `axum::serve` below blocks for the life of the process, so nothing can ever act on `indexer`
+    // completing during normal operation — `run_indexer` only returns once `retry_rpc` gives up on a
+    // transient RPC error, which should be rare. Watch for that in the background and flip /health
+    // unhealthy instead of letting the error vanish into an unawaited `JoinHandle`.
+    let watch_healthy = indexer_healthy.clone();
+    tokio::spawn(async move {
+        match indexer.await {
+            Ok(Ok(())) => error!("indexer task exited (it should run forever)"),
+            Ok(Err(e)) => error!("indexer task failed: {:?}", e),
+            Err(e) => error!("indexer task panicked: {:?}", e),
+        }
+        watch_healthy.store(false, Ordering::Relaxed);
+    });
 
     // Serve
     axum::serve(listener, app).await?;
-    indexer.await??;
     Ok(())
 }
 
-async fn run_indexer(provider: Arc<Provider<Ws>>, pool: SqlitePool, token: String, binance: HashSet<String>, net: Arc<RwLock<Netflow>>) -> Result<()> {
+async fn run_indexer(provider: Arc<MultiProvider>, storage: Arc<dyn Storage>, watch: Arc<WatchConfig>, net: Arc<RwLock<HashMap<(String, String), NetflowEntry>>>, ws_endpoint: Option<String>) -> Result<()> {
     info!("Starting indexer…");
-    let mut set = JoinSet::new();
 
-    // Poll-head loop: subscribe to new blocks and immediately filter logs for token Transfer events in that block
+    // Catch up on history first: if last_block is far behind the chain tip, paginate through
+    // wide block ranges instead of polling block-by-block, which would take far too long.
+    // `retry_rpc` absorbs transient RPC hiccups instead of a single one ending the indexer for
+    // good; `backfill` always resumes from the stored `last_block`, so retrying it from scratch
+    // on failure never reprocesses an already-committed block.
+    retry_rpc("backfill", || backfill(&provider, storage.as_ref(), &watch, &net)).await?;
+
     loop {
-        let latest: U64 = provider.get_block_number().await?;
-        let last: u64 = sqlx::query_scalar::<_, String>("SELECT value FROM state WHERE key='last_block'")
-            .fetch_optional(&pool).await?
-            .and_then(|v| v.parse::<u64>().ok())
-            .unwrap_or(latest.as_u64());
-
-        if latest.as_u64() > last {
-            // process each new block once
-            for b in (last+1)..=latest.as_u64() {
-                if let Err(e) = process_block(&provider, &pool, &token, &binance, b, &net).await {
-                    error!("process_block {} failed: {:?}", b, e);
-                } else {
-                    sqlx::query("INSERT OR REPLACE INTO state (key, value) VALUES ('last_block', ?)")
-                        .bind(b.to_string()).execute(&pool).await?;
-                }
+        if let Some(url) = &ws_endpoint {
+            // Never returns Ok: it only resolves once the subscription errors or the stream ends.
+            if let Err(e) = subscribe_blocks(url, &provider, storage.as_ref(), &watch, &net).await {
+                warn!("block subscription dropped, falling back to polling: {:?}", e);
             }
         }
 
-        // sleep briefly
+        // Polling fallback: used when no ws_endpoint is configured, or the subscription above
+        // just errored/disconnected. Keeps indexing (at the cost of latency) until the next loop
+        // iteration retries the subscription.
+        let latest: U64 = retry_rpc("get_block_number", || async { provider.get_block_number().await }).await?;
+        retry_rpc("catch_up_to", || catch_up_to(&provider, storage.as_ref(), &watch, &net, latest.as_u64())).await?;
         tokio::time::sleep(std::time::Duration::from_millis(900)).await;
     }
 }
 
-async fn process_block(provider: &Provider<Ws>, pool: &SqlitePool, token: &str, binance: &HashSet<String>, block_number: u64, net: &Arc<RwLock<Netflow>>) -> Result<()> {
-    // Build a filter for the token Transfer topic in this block
-    let token_addr = H160::from_str(token).context("bad token address")?;
+/// Bounded retry-with-backoff for the live indexing path's RPC calls. A lone transient failure
+/// (a dropped connection, a momentary node hiccup) used to propagate straight out of `run_indexer`,
+/// silently ending it for good — `axum::serve` keeps blocking regardless, so nothing downstream
+/// ever saw the error. Retrying here gives transient failures a chance to clear on their own; once
+/// `INDEXER_RETRY_ATTEMPTS` is exhausted the error still propagates, and the caller in `main` is
+/// responsible for surfacing that as the indexer task actually ending.
+async fn retry_rpc<T, F, Fut>(what: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut delay_ms = INDEXER_RETRY_BASE_DELAY_MS;
+    for attempt in 1..=INDEXER_RETRY_ATTEMPTS {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < INDEXER_RETRY_ATTEMPTS => {
+                warn!("{} failed (attempt {}/{}): {:?}, retrying in {}ms", what, attempt, INDEXER_RETRY_ATTEMPTS, e, delay_ms);
+                tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+                delay_ms = (delay_ms * 2).min(INDEXER_RETRY_MAX_DELAY_MS);
+            }
+            Err(e) => return Err(e).context(format!("{} failed after {} attempts", what, INDEXER_RETRY_ATTEMPTS)),
+        }
+    }
+    unreachable!("loop always returns on the final attempt")
+}
+
+/// Subscribes to new block headers over a dedicated WebSocket connection and processes the gap
+/// between `last_block` and each arriving header as it's announced, cutting detection latency to
+/// near-real-time and avoiding the redundant `get_block_number` polling of the fallback path. The
+/// quorum `MultiProvider` is still used for `get_logs`/`get_block` so log data isn't trusted from
+/// a single node. Returns (with an error) as soon as the subscription errors or the stream ends,
+/// so the caller can fall back to polling; `catch_up_to`'s gap-filling guarantees no block is
+/// skipped across that reconnect.
+async fn subscribe_blocks(ws_url: &str, provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>) -> Result<()> {
+    let ws = Ws::connect(ws_url).await.context("ws connect failed")?;
+    let ws_provider = Provider::new(ws);
+    let mut headers = ws_provider.subscribe_blocks().await.context("subscribe_blocks failed")?;
+    info!("subscribed to new block headers over {}", ws_url);
+
+    while let Some(header) = headers.next().await {
+        let Some(number) = header.number else { continue };
+        if let Err(e) = catch_up_to(provider, storage, watch, net, number.as_u64()).await {
+            error!("catch_up_to {} failed: {:?}", number.as_u64(), e);
+        }
+    }
+
+    Err(anyhow!("block subscription stream ended"))
+}
+
+/// Reconciles any reorg since `last_block`, then processes every block up to and including
+/// `target` once. Shared by both the WebSocket subscription path and the polling fallback so they
+/// behave identically regardless of how a new head was discovered.
+async fn catch_up_to(provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>, target: u64) -> Result<()> {
+    let mut last: u64 = storage.load_last_block().await?.unwrap_or(target);
+
+    // Before extending the chain, make sure what we already committed is still canonical.
+    if last > 0 {
+        let ancestor = find_common_ancestor(provider, storage, last).await?;
+        if ancestor < last {
+            let touched = storage.rollback_to(ancestor, last).await?;
+            update_netflow(watch, net, touched).await;
+            warn!("reorg: rolled back blocks {}..={}, last_block reset to {}", ancestor + 1, last, ancestor);
+            last = ancestor;
+        }
+    }
+
+    metrics::CHAIN_LAG.set(target.saturating_sub(last) as i64);
+
+    if target > last {
+        for b in (last+1)..=target {
+            if let Err(e) = process_block(provider, storage, watch, b, net).await {
+                error!("process_block {} failed: {:?}", b, e);
+                // Stop at the failed block so `last_block` never moves past it; the next call
+                // resumes from here.
+                break;
+            }
+            storage.save_block_state(b).await?;
+            metrics::CHAIN_LAG.set(target.saturating_sub(b) as i64);
+        }
+    }
+
+    Ok(())
+}
+
+/// Walk `last_block..chain_tip` in wide ranges via `get_logs`, shrinking the window whenever the
+/// node complains the range is too wide (mirrors the retry/backoff ethers' `LogQuery` pagination
+/// uses for providers with a "more than N results" / block-range cap), and growing it back toward
+/// `BACKFILL_MAX_WINDOW` on success. Returns once `last_block` has caught up to the tip observed
+/// at the time the loop checks, handing off to the live poll loop in `run_indexer`.
+async fn backfill(provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>) -> Result<()> {
+    let token_addrs = watch.token_addresses()?;
+    let mut window = BACKFILL_INITIAL_WINDOW;
+    let mut rate_limit_delay_ms = BACKFILL_RATE_LIMIT_BASE_DELAY_MS;
+
+    loop {
+        let tip: u64 = provider.get_block_number().await?.as_u64();
+        // `last_block` is the last block already processed (same convention as `catch_up_to`),
+        // so the window starts one past it and never re-queries a block we've already committed.
+        let from: u64 = storage.load_last_block().await?.map(|l| l + 1).unwrap_or(tip);
+
+        // Caught up with the tip observed at loop entry; hand off to the live poll loop.
+        if from > tip {
+            return Ok(());
+        }
+
+        let to = (from + window - 1).min(tip);
+        let filter = Filter::new()
+            .address(token_addrs.clone())
+            .from_block(BlockNumber::Number(from.into()))
+            .to_block(BlockNumber::Number(to.into()))
+            .topic0(*TRANSFER_TOPIC);
+
+        match provider.get_logs(&filter).await {
+            Ok(mut logs) => {
+                info!("backfill: processed blocks {}..={} ({} logs, window={})", from, to, logs.len(), window);
+                logs.sort_by_key(|l| (l.block_number.unwrap_or_default(), l.log_index.unwrap_or_default()));
+                process_logs(provider, storage, watch, logs, net).await?;
+                storage.save_block_state(to).await?;
+                window = (window * 2).min(BACKFILL_MAX_WINDOW);
+                rate_limit_delay_ms = BACKFILL_RATE_LIMIT_BASE_DELAY_MS;
+            }
+            Err(e) if is_rate_limited(&e) => {
+                warn!("backfill: range {}..={} rate-limited ({:?}), retrying same window in {}ms", from, to, e, rate_limit_delay_ms);
+                tokio::time::sleep(std::time::Duration::from_millis(rate_limit_delay_ms)).await;
+                rate_limit_delay_ms = (rate_limit_delay_ms * 2).min(BACKFILL_RATE_LIMIT_MAX_DELAY_MS);
+            }
+            Err(e) if is_range_too_wide(&e) && window > BACKFILL_MIN_WINDOW => {
+                window = (window / 2).max(BACKFILL_MIN_WINDOW);
+                warn!("backfill: range {}..={} rejected ({:?}), shrinking window to {}", from, to, e, window);
+            }
+            Err(e) => return Err(e).context(format!("backfill get_logs {}..={} failed", from, to)),
+        }
+    }
+}
+
+/// Best-effort detection of a provider telling us a log query spans too many blocks or would
+/// return too many results. Matches only genuine range/result-count phrasing — notably not the
+/// bare word "limit", which also shows up in rate-limit errors and would otherwise make backfill
+/// shrink its window (and retry instantly, with no backoff) against a node that's merely
+/// throttling it.
+fn is_range_too_wide(err: &anyhow::Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    msg.contains("query returned more than") || msg.contains("block range") && msg.contains("too") || msg.contains("exceeds the range")
+}
+
+/// Best-effort detection of a provider throttling us (HTTP 429 or a "rate limit" style message).
+/// Unlike `is_range_too_wide`, this calls for backing off and retrying the *same* window, not
+/// shrinking it — the range wasn't the problem.
+fn is_rate_limited(err: &anyhow::Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    msg.contains("rate limit") || msg.contains("too many requests") || msg.contains("429")
+}
+
+async fn process_block(provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, block_number: u64, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>) -> Result<()> {
+    // Build a filter for every watched token's Transfer topic in this block
     let filter = Filter::new()
-        .address(token_addr)
+        .address(watch.token_addresses()?)
         .from_block(BlockNumber::Number(block_number.into()))
         .to_block(BlockNumber::Number(block_number.into()))
         .topic0(*TRANSFER_TOPIC);
 
     let logs: Vec<Log> = provider.get_logs(&filter).await?;
-    if logs.is_empty() {
-        return Ok(());
+    // Unlike the ranged backfill path, the live path must commit every block it steps through —
+    // including ones with no matched transfers — so `processed_blocks` has a hash for each one
+    // and `find_common_ancestor` has something to compare against on the next reorg check.
+    commit_single_block(provider, storage, watch, block_number, logs, net).await
+}
+
+/// Shared accounting path for a batch of already-fetched Transfer logs: decodes each into a
+/// `Transfer`, folds it into a raw+human delta per (token, exchange) pair it touches, and hands
+/// each block's batch to `commit_single_block`. Used by the ranged backfill path; blocks with no
+/// matched transfer in the range are skipped (acceptable for already-settled history, since
+/// backfill never runs reorg detection).
+async fn process_logs(provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, logs: Vec<Log>, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>) -> Result<()> {
+    let mut by_block: std::collections::BTreeMap<u64, Vec<Log>> = std::collections::BTreeMap::new();
+    for lg in logs {
+        by_block.entry(lg.block_number.unwrap_or_default().as_u64()).or_default().push(lg);
     }
 
-    // Prepare a tx
-    let mut tx = pool.begin().await?;
+    for (block_num, block_logs) in by_block {
+        commit_single_block(provider, storage, watch, block_num, block_logs, net).await?;
+        // Advance `last_block` past each committed block so a retry after a failure later in the
+        // same range doesn't commit this block's deltas a second time.
+        storage.save_block_state(block_num).await?;
+    }
 
-    for lg in logs {
+    Ok(())
+}
+
+/// Decodes one block's Transfer logs (possibly none), folds them into a raw+human delta per
+/// (token, exchange) pair touched, and hands the result to `Storage::commit_block`, which inserts
+/// the transfers, applies the deltas to `netflow_totals`, and records the block's hash, all
+/// atomically — even when `block_logs` is empty, so every committed block gets a stored hash.
+async fn commit_single_block(provider: &MultiProvider, storage: &dyn Storage, watch: &WatchConfig, block_num: u64, block_logs: Vec<Log>, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>) -> Result<()> {
+    metrics::BLOCKS_PROCESSED.inc();
+    metrics::TRANSFER_LOGS_SEEN.inc_by(block_logs.len() as u64);
+
+    let block = provider.get_block(block_num).await?;
+    let ts = block.as_ref().map(|b| b.timestamp.as_u64() as i64).unwrap_or(0);
+    let block_hash = block.as_ref().and_then(|b| b.hash).map(|h| format!("{:#x}", h)).unwrap_or_default();
+
+    let mut transfers = Vec::with_capacity(block_logs.len());
+    // Raw (inflow, outflow) per (token_address, exchange) pair this block touched.
+    let mut raw_deltas: HashMap<(String, String), (U256, U256)> = HashMap::new();
+
+    for lg in block_logs {
         // ERC20 Transfer has topics: [transfer, from, to]; data = amount (uint256)
         if lg.topics.len() < 3 { continue; }
+        let token_addr = normalize(&format!("{:#x}", lg.address));
+        if !watch.tokens.contains_key(&token_addr) { continue; }
+
         let from = topic_to_addr(&lg.topics[1]);
         let to = topic_to_addr(&lg.topics[2]);
         let amount = U256::from_big_endian(&lg.data.to_vec());
 
-        // Fetch block timestamp if available
-        let ts = if let Some(bn) = lg.block_number {
-            if let Some(block) = provider.get_block(bn).await? {
-                block.timestamp.as_u64() as i64
-            } else { 0 }
-        } else { 0 };
-
-        // Save raw
         let hash = lg.transaction_hash.unwrap_or_default();
-        let tx_hash = format!("{:#x}", hash);
-        let block_num = lg.block_number.unwrap_or_default().as_u64() as i64;
-        let token_s = normalize(&format!("{:#x}", lg.address));
+        let log_index = lg.log_index.unwrap_or_default().as_u64();
         let from_s = normalize(&from);
         let to_s = normalize(&to);
-        let amount_dec = u256_to_decimal_string(amount);
-
-        sqlx::query(r#"
-            INSERT OR IGNORE INTO raw_transactions
-                (tx_hash, block_number, timestamp, from_address, to_address, token_address, amount)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-        "#)
-            .bind(&tx_hash)
-            .bind(block_num)
-            .bind(ts)
-            .bind(&from_s)
-            .bind(&to_s)
-            .bind(&token_s)
-            .bind(&amount_dec)
-            .execute(&mut *tx).await?;
-
-        // Update netflow totals when Binance is involved
-        let mut inflow = None;
-        let mut outflow = None;
-        if binance.contains(&to_s) {
-            inflow = Some(amount_dec.clone());
-        }
-        if binance.contains(&from_s) {
-            outflow = Some(amount_dec.clone());
-        }
-        if inflow.is_some() || outflow.is_some() {
-            // Read current
-            let (cur_in, cur_out): (String, String) = sqlx::query_as("SELECT inflow, outflow FROM netflow_totals WHERE id=1")
-                .fetch_one(&mut *tx).await?;
-            let new_in = add_decimal(&cur_in, inflow.as_deref().unwrap_or("0"));
-            let new_out = add_decimal(&cur_out, outflow.as_deref().unwrap_or("0"));
-            sqlx::query("UPDATE netflow_totals SET inflow=?, outflow=? WHERE id=1")
-                .bind(&new_in)
-                .bind(&new_out)
-                .execute(&mut *tx).await?;
-
-            let cumulative = sub_decimal(&new_in, &new_out);
-            // Update in-memory snapshot for HTTP
-            {
-                let mut guard = net.write().await;
-                guard.inflow = new_in;
-                guard.outflow = new_out;
-                guard.cumulative = cumulative;
+
+        transfers.push(Transfer {
+            tx_hash: format!("{:#x}", hash),
+            log_index,
+            block_number: block_num,
+            timestamp: ts,
+            from_address: from_s.clone(),
+            to_address: to_s.clone(),
+            token_address: token_addr.clone(),
+            amount: u256_to_decimal_string(amount),
+        });
+
+        let mut matched_any_exchange = false;
+        for (exchange, addrs) in &watch.exchanges {
+            let to_match = addrs.contains(&to_s);
+            let from_match = addrs.contains(&from_s);
+            if !to_match && !from_match {
+                continue;
             }
+            matched_any_exchange = true;
+            let entry = raw_deltas.entry((token_addr.clone(), exchange.clone())).or_insert((U256::zero(), U256::zero()));
+            if to_match {
+                entry.0 += amount;
+            }
+            if from_match {
+                entry.1 += amount;
+            }
+        }
+        if matched_any_exchange {
+            metrics::EXCHANGE_TRANSFERS.inc();
         }
     }
 
-    tx.commit().await?;
+    let deltas: Vec<NetflowDelta> = raw_deltas.into_iter().map(|((token_address, exchange), (inflow, outflow))| {
+        let decimals = watch.tokens.get(&token_address).map(|t| t.decimals).unwrap_or(0);
+        NetflowDelta {
+            token_address,
+            exchange,
+            inflow_delta: inflow.to_string(),
+            outflow_delta: outflow.to_string(),
+            inflow_delta_human: u256_to_human_string(inflow, decimals),
+            outflow_delta_human: u256_to_human_string(outflow, decimals),
+        }
+    }).collect();
+
+    let touched = storage.commit_block(block_num, &block_hash, &transfers, &deltas).await?;
+    update_netflow(watch, net, touched).await;
     Ok(())
 }
 
+/// Builds the HTTP-facing `NetflowEntry` for a (token, exchange) pair's updated totals, looking up
+/// the token's symbol for display (falling back to its address if it isn't in `watch.tokens`,
+/// which shouldn't happen for anything this indexer itself wrote).
+fn netflow_entry(watch: &WatchConfig, total: NetflowTotal) -> NetflowEntry {
+    let symbol = watch.tokens.get(&total.token_address).map(|t| t.symbol.clone()).unwrap_or_else(|| total.token_address.clone());
+    let cumulative = sub_decimal(&total.inflow, &total.outflow);
+    let cumulative_human = sub_human(&total.inflow_human, &total.outflow_human);
+    NetflowEntry {
+        token_symbol: symbol,
+        token_address: total.token_address,
+        exchange: total.exchange,
+        inflow: total.inflow,
+        outflow: total.outflow,
+        cumulative,
+        inflow_human: total.inflow_human,
+        outflow_human: total.outflow_human,
+        cumulative_human,
+    }
+}
+
+async fn update_netflow(watch: &WatchConfig, net: &Arc<RwLock<HashMap<(String, String), NetflowEntry>>>, touched: Vec<NetflowTotal>) {
+    if touched.is_empty() {
+        return;
+    }
+    let mut guard = net.write().await;
+    for total in touched {
+        guard.insert((total.token_address.clone(), total.exchange.clone()), netflow_entry(watch, total));
+    }
+}
+
+/// Walk backward from `from_block` comparing our stored `processed_blocks.block_hash` against the
+/// on-chain hash, stopping at the first block where they agree (the common ancestor). Blocks we
+/// have no stored hash for (e.g. never processed) are treated as agreeing, since there is nothing
+/// to reconcile.
+async fn find_common_ancestor(provider: &MultiProvider, storage: &dyn Storage, mut from_block: u64) -> Result<u64> {
+    loop {
+        if from_block == 0 {
+            return Ok(0);
+        }
+        let Some(stored_hash) = storage.stored_block_hash(from_block).await? else { return Ok(from_block) };
+
+        let onchain_hash = provider.get_block(from_block).await?.and_then(|b| b.hash).map(|h| format!("{:#x}", h));
+        if onchain_hash.as_deref() == Some(stored_hash.as_str()) {
+            return Ok(from_block);
+        }
+        warn!("reorg: block {} stored hash {} diverges from chain, walking back", from_block, stored_hash);
+        from_block -= 1;
+    }
+}
+
 // Helpers
 fn normalize(s: &str) -> String {
     s.trim().to_lowercase()
@@ -243,15 +572,16 @@ fn u256_to_decimal_string(v: U256) -> String {
     v.to_string()
 }
 
-// Naive decimal add/sub using big integers via U256 parsing from string
-fn add_decimal(a: &str, b: &str) -> String {
-    let ua = U256::from_dec_str(a).unwrap_or(U256::ZERO);
-    let ub = U256::from_dec_str(b).unwrap_or(U256::ZERO);
-    (ua + ub).to_string()
+/// Scales a raw on-chain amount down by `10^decimals` into a fixed-point decimal string, the way
+/// the token's own contract would display it (e.g. 1_500_000 wei at 6 decimals -> "1.500000").
+fn u256_to_human_string(v: U256, decimals: u32) -> String {
+    if decimals == 0 {
+        return v.to_string();
+    }
+    let divisor = U256::from(10u64).pow(U256::from(decimals));
+    let whole = v / divisor;
+    let frac = v % divisor;
+    let frac_str = format!("{:0>width$}", frac.to_string(), width = decimals as usize);
+    format!("{whole}.{frac_str}")
 }
 
-fn sub_decimal(a: &str, b: &str) -> String {
-    let ua = U256::from_dec_str(a).unwrap_or(U256::ZERO);
-    let ub = U256::from_dec_str(b).unwrap_or(U256::ZERO);
-    if ua >= ub { (ua - ub).to_string() } else { "0".into() }
-}
@@ -0,0 +1,58 @@
+use once_cell::sync::Lazy;
+use prometheus::{exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry, TextEncoder};
+
+/// Home for every metric this indexer exports; kept separate from the default global registry so
+/// `/metrics` only ever reports what this binary actually produces.
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+pub static BLOCKS_PROCESSED: Lazy<IntCounter> = Lazy::new(|| {
+    register_counter("indexer_blocks_processed_total", "Total number of blocks the indexer has processed")
+});
+
+pub static TRANSFER_LOGS_SEEN: Lazy<IntCounter> = Lazy::new(|| {
+    register_counter("indexer_transfer_logs_seen_total", "Total Transfer logs observed for the watched tokens")
+});
+
+pub static EXCHANGE_TRANSFERS: Lazy<IntCounter> = Lazy::new(|| {
+    register_counter("indexer_exchange_transfers_total", "Total transfers where a configured exchange address was sender or recipient")
+});
+
+pub static CHAIN_LAG: Lazy<IntGauge> = Lazy::new(|| {
+    let g = IntGauge::new("indexer_chain_lag_blocks", "Chain head minus last_block, as of the most recent check").unwrap();
+    REGISTRY.register(Box::new(g.clone())).unwrap();
+    g
+});
+
+pub static GET_LOGS_LATENCY_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_latency_histogram("indexer_get_logs_latency_seconds", "Latency of get_logs RPC calls")
+});
+
+pub static GET_BLOCK_LATENCY_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_latency_histogram("indexer_get_block_latency_seconds", "Latency of get_block RPC calls")
+});
+
+pub static DB_COMMIT_LATENCY_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_latency_histogram("indexer_db_commit_latency_seconds", "Latency of the per-block storage commit (Storage::commit_block)")
+});
+
+fn register_counter(name: &str, help: &str) -> IntCounter {
+    let c = IntCounter::with_opts(Opts::new(name, help)).unwrap();
+    REGISTRY.register(Box::new(c.clone())).unwrap();
+    c
+}
+
+/// 5ms..~10s exponential buckets, wide enough to cover both fast local SQLite commits and slow
+/// RPC calls to a congested public node, while keeping a fixed shape operators can alert on.
+fn register_latency_histogram(name: &str, help: &str) -> Histogram {
+    let opts = HistogramOpts::new(name, help).buckets(exponential_buckets(0.005, 2.0, 12).unwrap());
+    let h = Histogram::with_opts(opts).unwrap();
+    REGISTRY.register(Box::new(h.clone())).unwrap();
+    h
+}
+
+/// Renders every registered metric in Prometheus text exposition format for the `/metrics` route.
+pub fn render() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf).expect("metrics encoding is infallible for well-formed collectors");
+    String::from_utf8(buf).expect("prometheus text encoding is always valid UTF-8")
+}
@@ -0,0 +1,139 @@
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use ethers::prelude::*;
+use futures::future::join_all;
+use tracing::warn;
+
+use crate::metrics;
+
+/// Dispatches reads to a pool of independent JSON-RPC endpoints and accepts a result once a
+/// configurable quorum of them agree, modeled after ethers' `QuorumProvider`.
+///
+/// `get_logs`/`get_block` feed data straight into netflow accounting, so they require real
+/// agreement: when fewer than `quorum` endpoints agree, the call fails rather than returning an
+/// unvalidated single response — a lone honest-looking but wrong reply (or a Byzantine one) must
+/// never be mistaken for a validated value. `get_logs` responses are sorted before comparison so
+/// endpoints that agree on the same set of logs in a different order still count as agreeing.
+///
+/// `get_block_number` is the one exception: it's only used to know how far to catch up, never
+/// trusted verbatim, and honest endpoints routinely observe slightly different chain tips, so
+/// exact-match quorum on it would fail almost every call. That call falls back to the first
+/// healthy response when quorum isn't reached, so a single bad or stalled node degrades indexing
+/// instead of halting it; every other call only falls back to a healthy endpoint on that
+/// endpoint's own transport error.
+pub struct MultiProvider {
+    providers: Vec<Provider<Http>>,
+    quorum: usize,
+}
+
+impl MultiProvider {
+    pub fn new(endpoints: &[String], quorum: usize) -> Result<Self> {
+        if endpoints.is_empty() {
+            return Err(anyhow!("at least one RPC endpoint is required"));
+        }
+        let providers = endpoints
+            .iter()
+            .map(|url| {
+                Provider::<Http>::try_from(url.as_str())
+                    .map_err(|e| anyhow!("bad RPC endpoint {}: {}", url, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let quorum = quorum.clamp(1, providers.len());
+        Ok(Self { providers, quorum })
+    }
+
+    pub async fn get_block_number(&self) -> Result<U64> {
+        self.quorum_call(|p| async move { p.get_block_number().await }, true)
+            .await
+    }
+
+    pub async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        let filter = filter.clone();
+        let start = Instant::now();
+        let result = self
+            .quorum_call(|p| {
+                let filter = filter.clone();
+                async move {
+                    // Sort before the caller compares responses for agreement: honest nodes can
+                    // return the same set of logs in different orders, and an order-sensitive
+                    // equality check would treat that as disagreement.
+                    let mut logs = p.get_logs(&filter).await?;
+                    logs.sort_by_key(|l| (l.block_number.unwrap_or_default(), l.log_index.unwrap_or_default()));
+                    Ok(logs)
+                }
+            }, false)
+            .await;
+        metrics::GET_LOGS_LATENCY_SECONDS.observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    pub async fn get_block(&self, block_number: u64) -> Result<Option<Block<H256>>> {
+        let start = Instant::now();
+        let result = self
+            .quorum_call(|p| async move { p.get_block(block_number).await }, false)
+            .await;
+        metrics::GET_BLOCK_LATENCY_SECONDS.observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Fan a read out to every endpoint concurrently, tally identical successful responses, and
+    /// return the value `quorum` of them agree on. When `allow_unvalidated_fallback` is true and
+    /// quorum isn't reached, falls back to the first healthy response instead of failing — see
+    /// the struct doc for why only `get_block_number` opts into that. Otherwise, failing to reach
+    /// quorum is itself an error, even if every endpoint that answered did so without a transport
+    /// error.
+    async fn quorum_call<F, Fut, T>(&self, f: F, allow_unvalidated_fallback: bool) -> Result<T>
+    where
+        F: Fn(&Provider<Http>) -> Fut,
+        Fut: std::future::Future<Output = std::result::Result<T, ProviderError>>,
+        T: Clone + PartialEq,
+    {
+        let results = join_all(self.providers.iter().map(|p| f(p))).await;
+
+        let mut tallies: Vec<(T, usize)> = Vec::new();
+        let mut first_ok: Option<T> = None;
+        let mut healthy = 0usize;
+        for r in &results {
+            if let Ok(v) = r {
+                healthy += 1;
+                if first_ok.is_none() {
+                    first_ok = Some(v.clone());
+                }
+                match tallies.iter_mut().find(|(existing, _)| existing == v) {
+                    Some(entry) => entry.1 += 1,
+                    None => tallies.push((v.clone(), 1)),
+                }
+            }
+        }
+
+        if let Some((value, count)) = tallies.iter().max_by_key(|(_, c)| *c) {
+            if *count >= self.quorum {
+                return Ok(value.clone());
+            }
+        }
+
+        if allow_unvalidated_fallback {
+            if let Some(value) = first_ok {
+                warn!(
+                    "quorum of {} not reached ({} of {} endpoints healthy, no agreement); falling back to first healthy response",
+                    self.quorum, healthy, self.providers.len()
+                );
+                return Ok(value);
+            }
+        }
+
+        if healthy > 0 {
+            return Err(anyhow!(
+                "quorum of {} not reached: {} of {} endpoints answered but did not agree",
+                self.quorum, healthy, self.providers.len()
+            ));
+        }
+
+        Err(anyhow!(
+            "all {} RPC endpoints failed: {:?}",
+            self.providers.len(),
+            results.into_iter().filter_map(|r| r.err()).collect::<Vec<_>>()
+        ))
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Instant;
+
+use thiserror::Error;
+use tracing::{info_span, Instrument};
+
+/// A database failure with enough context to pinpoint the exact failing statement: which DAL
+/// operation ran, which block (if any) it was processing, and the arguments involved (tx hash,
+/// addresses, ...). Wraps the underlying `sqlx::Error` so nothing about the original failure is
+/// lost.
+#[derive(Debug, Error)]
+pub enum DalError {
+    #[error("{operation} failed (block={block:?}, args={args}): {source}")]
+    Query {
+        operation: &'static str,
+        block: Option<u64>,
+        args: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("{operation} failed: {source}")]
+    Migration {
+        operation: &'static str,
+        #[source]
+        source: sqlx::migrate::MigrateError,
+    },
+}
+
+impl DalError {
+    fn query(operation: &'static str, block: Option<u64>, args: String, source: sqlx::Error) -> Self {
+        Self::Query { operation, block, args, source }
+    }
+
+    pub fn migration(operation: &'static str, source: sqlx::migrate::MigrateError) -> Self {
+        Self::Migration { operation, source }
+    }
+}
+
+/// Runs a single sqlx call inside a tracing span carrying the operation name, block number, and
+/// relevant args, and records its latency. On failure, wraps the `sqlx::Error` in a `DalError`
+/// that preserves this context, so a `process_block` failure logged by `error!` names the exact
+/// failing statement instead of a generic "query failed".
+pub async fn instrumented<T, Fut>(
+    operation: &'static str,
+    block: Option<u64>,
+    args: impl Into<String>,
+    fut: Fut,
+) -> Result<T, DalError>
+where
+    Fut: std::future::Future<Output = Result<T, sqlx::Error>>,
+{
+    let args = args.into();
+    let span = info_span!("dal_query", operation, block, args = %args);
+    let start = Instant::now();
+    let result = fut.instrument(span.clone()).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(v) => {
+            let _enter = span.enter();
+            tracing::trace!(latency_ms, "query ok");
+            Ok(v)
+        }
+        Err(source) => Err(DalError::query(operation, block, args, source)),
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+mod dal;
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// A single decoded ERC20 Transfer, ready to be persisted. Decoding/addressing stays in the
+/// indexer; Storage only ever sees this plain record.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub timestamp: i64,
+    pub from_address: String,
+    pub to_address: String,
+    pub token_address: String,
+    pub amount: String,
+}
+
+/// A netflow contribution a single block made to one (token, exchange) pair, in both raw
+/// (wei-scale) and human (decimal-scaled) form. Only pairs a block actually touched are passed to
+/// `commit_block`, and only those are reversed by `rollback_to`.
+#[derive(Debug, Clone)]
+pub struct NetflowDelta {
+    pub token_address: String,
+    pub exchange: String,
+    pub inflow_delta: String,
+    pub outflow_delta: String,
+    pub inflow_delta_human: String,
+    pub outflow_delta_human: String,
+}
+
+/// Running netflow totals for one (token, exchange) pair, in both raw and human-scaled form.
+#[derive(Debug, Clone)]
+pub struct NetflowTotal {
+    pub token_address: String,
+    pub exchange: String,
+    pub inflow: String,
+    pub outflow: String,
+    pub inflow_human: String,
+    pub outflow_human: String,
+}
+
+/// Persistence for the indexer, kept independent of which SQL backend is behind it. Every method
+/// that touches more than one table (`commit_block`, `rollback_to`) does so atomically so
+/// `raw_transactions`/`netflow_totals`/`block_deltas`/`processed_blocks` never drift out of sync
+/// with each other.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// The last block number the indexer has fully processed, if any.
+    async fn load_last_block(&self) -> Result<Option<u64>>;
+
+    /// Overwrite the stored `last_block` without touching any other state (used to seed the
+    /// starting point and to fast-forward `last_block` during backfill).
+    async fn save_block_state(&self, block_number: u64) -> Result<()>;
+
+    /// Current running netflow totals for every (token, exchange) pair seen so far.
+    async fn load_netflow_totals(&self) -> Result<Vec<NetflowTotal>>;
+
+    /// The block hash we recorded the last time we processed `block_number`, if any.
+    async fn stored_block_hash(&self, block_number: u64) -> Result<Option<String>>;
+
+    /// Insert every transfer in a block, fold each `NetflowDelta` into the matching (token,
+    /// exchange) row in `netflow_totals`, and record the block's hash plus its own per-pair deltas
+    /// for reorg rollback — all in one transaction. Returns the updated totals for every pair the
+    /// block touched.
+    async fn commit_block(
+        &self,
+        block_number: u64,
+        block_hash: &str,
+        transfers: &[Transfer],
+        deltas: &[NetflowDelta],
+    ) -> Result<Vec<NetflowTotal>>;
+
+    /// Reverse every orphaned block's per-pair contribution in `(ancestor, old_last]`, delete their
+    /// `raw_transactions`/`block_deltas`/`processed_blocks` rows, and rewind `last_block` to
+    /// `ancestor` — all in one transaction. Returns the updated totals for every pair touched by a
+    /// rolled-back block.
+    async fn rollback_to(&self, ancestor: u64, old_last: u64) -> Result<Vec<NetflowTotal>>;
+}
+
+/// Selects a backend from the `DATABASE_URL` scheme (`sqlite://` vs `postgres://`/`postgresql://`)
+/// and runs its matching migration set.
+pub async fn connect(database_url: &str) -> Result<Arc<dyn Storage>> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        Ok(Arc::new(PostgresStorage::connect(database_url).await?))
+    } else if database_url.starts_with("sqlite://") {
+        Ok(Arc::new(SqliteStorage::connect(database_url).await?))
+    } else {
+        Err(anyhow!("unsupported DATABASE_URL scheme: {}", database_url))
+    }
+}
+
+// Naive decimal add/sub using big integers via U256 parsing from string. Shared by every backend
+// since totals are stored as plain decimal strings regardless of SQL dialect.
+pub(crate) fn add_decimal(a: &str, b: &str) -> String {
+    use ethers::types::U256;
+    let ua = U256::from_dec_str(a).unwrap_or(U256::zero());
+    let ub = U256::from_dec_str(b).unwrap_or(U256::zero());
+    (ua + ub).to_string()
+}
+
+pub(crate) fn sub_decimal(a: &str, b: &str) -> String {
+    use ethers::types::U256;
+    let ua = U256::from_dec_str(a).unwrap_or(U256::zero());
+    let ub = U256::from_dec_str(b).unwrap_or(U256::zero());
+    if ua >= ub { (ua - ub).to_string() } else { "0".into() }
+}
+
+// Same idea as add_decimal/sub_decimal but for human (decimal-point) amounts, which U256 can't
+// parse directly. Pads both operands to a common fractional width, adds/subtracts as plain
+// integers, then reinserts the point — storage never needs to know a token's `decimals` to keep
+// these totals consistent.
+pub(crate) fn add_human(a: &str, b: &str) -> String {
+    use ethers::types::U256;
+    let (ia, fa) = split_fixed_point(a);
+    let (ib, fb) = split_fixed_point(b);
+    let scale = fa.len().max(fb.len());
+    let ua = U256::from_dec_str(&format!("{ia}{:0<scale$}", fa)).unwrap_or(U256::zero());
+    let ub = U256::from_dec_str(&format!("{ib}{:0<scale$}", fb)).unwrap_or(U256::zero());
+    join_fixed_point(ua + ub, scale)
+}
+
+pub(crate) fn sub_human(a: &str, b: &str) -> String {
+    use ethers::types::U256;
+    let (ia, fa) = split_fixed_point(a);
+    let (ib, fb) = split_fixed_point(b);
+    let scale = fa.len().max(fb.len());
+    let ua = U256::from_dec_str(&format!("{ia}{:0<scale$}", fa)).unwrap_or(U256::zero());
+    let ub = U256::from_dec_str(&format!("{ib}{:0<scale$}", fb)).unwrap_or(U256::zero());
+    let diff = if ua >= ub { ua - ub } else { U256::zero() };
+    join_fixed_point(diff, scale)
+}
+
+fn split_fixed_point(s: &str) -> (&str, &str) {
+    match s.split_once('.') {
+        Some((int_part, frac_part)) => (int_part, frac_part),
+        None => (s, ""),
+    }
+}
+
+fn join_fixed_point(value: ethers::types::U256, scale: usize) -> String {
+    if scale == 0 {
+        return value.to_string();
+    }
+    let digits = value.to_string();
+    let padded = format!("{digits:0>width$}", width = scale + 1);
+    let split_at = padded.len() - scale;
+    format!("{}.{}", &padded[..split_at], &padded[split_at..])
+}
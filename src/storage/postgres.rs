@@ -0,0 +1,257 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::{migrate::MigrateDatabase, PgPool, Postgres};
+
+use crate::metrics;
+
+use super::dal::instrumented;
+use super::{add_decimal, add_human, sub_decimal, sub_human, NetflowDelta, NetflowTotal, Storage, Transfer};
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        if !Postgres::database_exists(database_url).await.unwrap_or(false) {
+            Postgres::create_database(database_url).await?;
+        }
+        let pool = PgPool::connect(database_url).await?;
+        sqlx::migrate!("./migrations/postgres")
+            .run(&pool)
+            .await
+            .map_err(|e| super::dal::DalError::migration("migrate_postgres", e))?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn load_last_block(&self) -> Result<Option<u64>> {
+        let v: Option<String> = instrumented("load_last_block", None, "", async {
+            sqlx::query_scalar("SELECT value FROM state WHERE key='last_block'")
+                .fetch_optional(&self.pool).await
+        }).await?;
+        Ok(v.and_then(|v| v.parse::<u64>().ok()))
+    }
+
+    async fn save_block_state(&self, block_number: u64) -> Result<()> {
+        instrumented("save_block_state", Some(block_number), "", async {
+            sqlx::query(r#"
+                INSERT INTO state (key, value) VALUES ('last_block', $1)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value
+            "#)
+                .bind(block_number.to_string())
+                .execute(&self.pool).await
+        }).await?;
+        Ok(())
+    }
+
+    async fn load_netflow_totals(&self) -> Result<Vec<NetflowTotal>> {
+        let rows: Vec<(String, String, String, String, String, String)> = instrumented(
+            "load_netflow_totals", None, "", async {
+                sqlx::query_as("SELECT token_address, exchange, inflow, outflow, inflow_human, outflow_human FROM netflow_totals")
+                    .fetch_all(&self.pool).await
+            }
+        ).await?;
+        Ok(rows.into_iter()
+            .map(|(token_address, exchange, inflow, outflow, inflow_human, outflow_human)| {
+                NetflowTotal { token_address, exchange, inflow, outflow, inflow_human, outflow_human }
+            })
+            .collect())
+    }
+
+    async fn stored_block_hash(&self, block_number: u64) -> Result<Option<String>> {
+        let hash = instrumented("stored_block_hash", Some(block_number), "", async {
+            sqlx::query_scalar("SELECT block_hash FROM processed_blocks WHERE block_number=$1")
+                .bind(block_number as i64)
+                .fetch_optional(&self.pool).await
+        }).await?;
+        Ok(hash)
+    }
+
+    async fn commit_block(
+        &self,
+        block_number: u64,
+        block_hash: &str,
+        transfers: &[Transfer],
+        deltas: &[NetflowDelta],
+    ) -> Result<Vec<NetflowTotal>> {
+        let start = Instant::now();
+        let mut tx = self.pool.begin().await?;
+
+        for t in transfers {
+            instrumented("insert_transfer", Some(block_number), format!("{}/{}", t.tx_hash, t.log_index), async {
+                sqlx::query(r#"
+                    INSERT INTO raw_transactions
+                        (tx_hash, log_index, block_number, timestamp, from_address, to_address, token_address, amount)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    ON CONFLICT (tx_hash, log_index) DO NOTHING
+                "#)
+                    .bind(&t.tx_hash)
+                    .bind(t.log_index as i64)
+                    .bind(t.block_number as i64)
+                    .bind(t.timestamp)
+                    .bind(&t.from_address)
+                    .bind(&t.to_address)
+                    .bind(&t.token_address)
+                    .bind(&t.amount)
+                    .execute(&mut *tx).await
+            }).await?;
+        }
+
+        let mut updated = Vec::with_capacity(deltas.len());
+        for d in deltas {
+            instrumented("ensure_netflow_row", Some(block_number), format!("{}/{}", d.token_address, d.exchange), async {
+                sqlx::query(r#"
+                    INSERT INTO netflow_totals (token_address, exchange, inflow, outflow, inflow_human, outflow_human)
+                    VALUES ($1, $2, '0', '0', '0', '0')
+                    ON CONFLICT (token_address, exchange) DO NOTHING
+                "#)
+                    .bind(&d.token_address).bind(&d.exchange)
+                    .execute(&mut *tx).await
+            }).await?;
+
+            let (cur_in, cur_out, cur_in_human, cur_out_human): (String, String, String, String) = instrumented(
+                "load_netflow_totals", Some(block_number), format!("{}/{}", d.token_address, d.exchange), async {
+                    sqlx::query_as("SELECT inflow, outflow, inflow_human, outflow_human FROM netflow_totals WHERE token_address=$1 AND exchange=$2")
+                        .bind(&d.token_address).bind(&d.exchange)
+                        .fetch_one(&mut *tx).await
+                }
+            ).await?;
+
+            let new_in = add_decimal(&cur_in, &d.inflow_delta);
+            let new_out = add_decimal(&cur_out, &d.outflow_delta);
+            let new_in_human = add_human(&cur_in_human, &d.inflow_delta_human);
+            let new_out_human = add_human(&cur_out_human, &d.outflow_delta_human);
+
+            instrumented("apply_netflow_delta", Some(block_number), format!("{}/{} +{}/-{}", d.token_address, d.exchange, d.inflow_delta, d.outflow_delta), async {
+                sqlx::query("UPDATE netflow_totals SET inflow=$1, outflow=$2, inflow_human=$3, outflow_human=$4 WHERE token_address=$5 AND exchange=$6")
+                    .bind(&new_in).bind(&new_out).bind(&new_in_human).bind(&new_out_human)
+                    .bind(&d.token_address).bind(&d.exchange)
+                    .execute(&mut *tx).await
+            }).await?;
+
+            instrumented("record_block_delta", Some(block_number), format!("{}/{}", d.token_address, d.exchange), async {
+                sqlx::query(r#"
+                    INSERT INTO block_deltas
+                        (block_number, token_address, exchange, inflow_delta, outflow_delta, inflow_delta_human, outflow_delta_human)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    ON CONFLICT (block_number, token_address, exchange) DO UPDATE SET
+                        inflow_delta = excluded.inflow_delta,
+                        outflow_delta = excluded.outflow_delta,
+                        inflow_delta_human = excluded.inflow_delta_human,
+                        outflow_delta_human = excluded.outflow_delta_human
+                "#)
+                    .bind(block_number as i64)
+                    .bind(&d.token_address).bind(&d.exchange)
+                    .bind(&d.inflow_delta).bind(&d.outflow_delta)
+                    .bind(&d.inflow_delta_human).bind(&d.outflow_delta_human)
+                    .execute(&mut *tx).await
+            }).await?;
+
+            updated.push(NetflowTotal {
+                token_address: d.token_address.clone(),
+                exchange: d.exchange.clone(),
+                inflow: new_in,
+                outflow: new_out,
+                inflow_human: new_in_human,
+                outflow_human: new_out_human,
+            });
+        }
+
+        instrumented("record_processed_block", Some(block_number), block_hash, async {
+            sqlx::query(r#"
+                INSERT INTO processed_blocks (block_number, block_hash)
+                VALUES ($1, $2)
+                ON CONFLICT (block_number) DO UPDATE SET block_hash = excluded.block_hash
+            "#)
+                .bind(block_number as i64)
+                .bind(block_hash)
+                .execute(&mut *tx).await
+        }).await?;
+
+        tx.commit().await?;
+        metrics::DB_COMMIT_LATENCY_SECONDS.observe(start.elapsed().as_secs_f64());
+        Ok(updated)
+    }
+
+    async fn rollback_to(&self, ancestor: u64, old_last: u64) -> Result<Vec<NetflowTotal>> {
+        let mut tx = self.pool.begin().await?;
+
+        let orphaned: Vec<(String, String, String, String, String, String)> = instrumented(
+            "load_orphaned_deltas", Some(old_last), format!("ancestor={ancestor}"), async {
+                sqlx::query_as(
+                    "SELECT token_address, exchange, inflow_delta, outflow_delta, inflow_delta_human, outflow_delta_human
+                     FROM block_deltas WHERE block_number > $1 AND block_number <= $2"
+                ).bind(ancestor as i64).bind(old_last as i64).fetch_all(&mut *tx).await
+            }
+        ).await?;
+
+        let mut updated = Vec::new();
+        if !orphaned.is_empty() {
+            let mut touched: Vec<(String, String)> = Vec::new();
+            for (token_address, exchange, ..) in &orphaned {
+                let key = (token_address.clone(), exchange.clone());
+                if !touched.contains(&key) {
+                    touched.push(key);
+                }
+            }
+
+            for (token_address, exchange) in touched {
+                let (mut new_in, mut new_out, mut new_in_human, mut new_out_human): (String, String, String, String) = instrumented(
+                    "load_netflow_totals", Some(old_last), format!("{token_address}/{exchange}"), async {
+                        sqlx::query_as("SELECT inflow, outflow, inflow_human, outflow_human FROM netflow_totals WHERE token_address=$1 AND exchange=$2")
+                            .bind(&token_address).bind(&exchange)
+                            .fetch_one(&mut *tx).await
+                    }
+                ).await?;
+
+                for (t, e, inflow_delta, outflow_delta, inflow_delta_human, outflow_delta_human) in &orphaned {
+                    if t != &token_address || e != &exchange { continue; }
+                    new_in = sub_decimal(&new_in, inflow_delta);
+                    new_out = sub_decimal(&new_out, outflow_delta);
+                    new_in_human = sub_human(&new_in_human, inflow_delta_human);
+                    new_out_human = sub_human(&new_out_human, outflow_delta_human);
+                }
+
+                instrumented("apply_netflow_delta", Some(old_last), format!("{token_address}/{exchange} rollback to ancestor={ancestor}"), async {
+                    sqlx::query("UPDATE netflow_totals SET inflow=$1, outflow=$2, inflow_human=$3, outflow_human=$4 WHERE token_address=$5 AND exchange=$6")
+                        .bind(&new_in).bind(&new_out).bind(&new_in_human).bind(&new_out_human)
+                        .bind(&token_address).bind(&exchange)
+                        .execute(&mut *tx).await
+                }).await?;
+
+                updated.push(NetflowTotal {
+                    token_address: token_address.clone(), exchange: exchange.clone(),
+                    inflow: new_in, outflow: new_out, inflow_human: new_in_human, outflow_human: new_out_human,
+                });
+            }
+        }
+
+        instrumented("delete_raw_transactions", Some(old_last), format!("ancestor={ancestor}"), async {
+            sqlx::query("DELETE FROM raw_transactions WHERE block_number > $1 AND block_number <= $2")
+                .bind(ancestor as i64).bind(old_last as i64).execute(&mut *tx).await
+        }).await?;
+        instrumented("delete_block_deltas", Some(old_last), format!("ancestor={ancestor}"), async {
+            sqlx::query("DELETE FROM block_deltas WHERE block_number > $1 AND block_number <= $2")
+                .bind(ancestor as i64).bind(old_last as i64).execute(&mut *tx).await
+        }).await?;
+        instrumented("delete_processed_blocks", Some(old_last), format!("ancestor={ancestor}"), async {
+            sqlx::query("DELETE FROM processed_blocks WHERE block_number > $1 AND block_number <= $2")
+                .bind(ancestor as i64).bind(old_last as i64).execute(&mut *tx).await
+        }).await?;
+        instrumented("save_block_state", Some(ancestor), "rollback", async {
+            sqlx::query(r#"
+                INSERT INTO state (key, value) VALUES ('last_block', $1)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value
+            "#)
+                .bind(ancestor.to_string()).execute(&mut *tx).await
+        }).await?;
+
+        tx.commit().await?;
+        Ok(updated)
+    }
+}